@@ -0,0 +1,10 @@
+/// Abstraction over a fixed-size block storage device.
+///
+/// Implementors read and write whole blocks identified by a block id; the
+/// caller is responsible for sizing `buf` to the device's block size.
+/// `bread` takes `&mut self` so a caching layer can update its recency
+/// tracking on a read, not just a write.
+pub trait BlockDev {
+    fn bread(&mut self, buf: &mut [u8], bid: usize);
+    fn bwrite(&mut self, buf: &[u8], bid: usize);
+}
@@ -0,0 +1,83 @@
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+
+use crate::fs::bio::BlockDev;
+
+struct CachedBlock {
+    data: Box<[u8]>,
+    dirty: bool,
+}
+
+/// Write-back block cache sitting in front of a raw `BlockDev`. Keeps up to
+/// `capacity` blocks in memory; dirty blocks are flushed to the underlying
+/// device on eviction, an explicit `sync`, or when the cache is dropped.
+pub struct BlockCache<D: BlockDev> {
+    dev: D,
+    block_size: usize,
+    entries: LruCache<usize, CachedBlock>,
+}
+
+impl<D: BlockDev> BlockCache<D> {
+    pub fn new(dev: D, block_size: usize, capacity: usize) -> BlockCache<D> {
+        BlockCache {
+            dev,
+            block_size,
+            entries: LruCache::new(NonZeroUsize::new(capacity).expect("cache capacity must be nonzero")),
+        }
+    }
+
+    fn load(&mut self, bid: usize) {
+        if self.entries.contains(&bid) {
+            return;
+        }
+        let mut data = vec![0u8; self.block_size].into_boxed_slice();
+        self.dev.bread(&mut data, bid);
+        if let Some((evicted_bid, evicted)) = self.entries.push(bid, CachedBlock { data, dirty: false }) {
+            if evicted.dirty {
+                self.dev.bwrite(&evicted.data, evicted_bid);
+            }
+        }
+    }
+
+    /// Fetch-or-load `bid` and return an immutable view of its contents.
+    pub fn get(&mut self, bid: usize) -> &[u8] {
+        self.load(bid);
+        &self.entries.get(&bid).unwrap().data
+    }
+
+    /// Fetch-or-load `bid`, mark it dirty, and return a mutable view of its contents.
+    pub fn get_mut(&mut self, bid: usize) -> &mut [u8] {
+        self.load(bid);
+        let entry = self.entries.get_mut(&bid).unwrap();
+        entry.dirty = true;
+        &mut entry.data
+    }
+
+    /// Write every dirty block back to the underlying device.
+    pub fn sync(&mut self) {
+        for (&bid, entry) in self.entries.iter_mut() {
+            if entry.dirty {
+                self.dev.bwrite(&entry.data, bid);
+                entry.dirty = false;
+            }
+        }
+    }
+}
+
+impl<D: BlockDev> BlockDev for BlockCache<D> {
+    fn bread(&mut self, buf: &mut [u8], bid: usize) {
+        buf.copy_from_slice(self.get(bid));
+    }
+
+    fn bwrite(&mut self, buf: &[u8], bid: usize) {
+        self.get_mut(bid).copy_from_slice(buf);
+    }
+}
+
+impl<D: BlockDev> Drop for BlockCache<D> {
+    /// Flush every dirty block so dropping the cache doesn't silently lose writes.
+    fn drop(&mut self) {
+        self.sync();
+    }
+}
@@ -0,0 +1,351 @@
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use crate::fs::bio::BlockDev;
+use crate::fs::ext2::{ Ext2Fs, BLOCK_SZ, ROOT_INO };
+use crate::fs::file::{ Dirent, NAME_LEN, FT_DIR, FT_REG_FILE, FT_SYMLINK };
+use crate::fs::inode::{ access, Inode, N_BLOCKS, W_OK, X_OK };
+
+/// Hop limit while following a chain of symlinks in `namei`, guarding against cycles.
+const MAX_SYMLINK_HOPS: u32 = 40;
+
+/// Size of a dirent's fixed header: inode (4) + entry_length (2) + name_length (1) + file_type (1).
+const DIRENT_HEADER_SZ: usize = 8;
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Current time in seconds since the epoch, for stamping new inodes.
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn padded_name(name: &[u8]) -> [u8; NAME_LEN] {
+    let mut buf = [0u8; NAME_LEN];
+    buf[.. name.len()].copy_from_slice(name);
+    buf
+}
+
+/// Decode the dirent header and active name bytes starting at `off` in `buf`.
+fn decode_dirent(buf: &[u8], off: usize) -> Dirent {
+    let inode = u32::from_le_bytes(buf[off .. off + 4].try_into().unwrap());
+    let entry_length = u16::from_le_bytes(buf[off + 4 .. off + 6].try_into().unwrap());
+    let name_length = buf[off + 6];
+    let file_type = buf[off + 7];
+    let mut name = [0u8; NAME_LEN];
+    let n = name_length as usize;
+    name[.. n].copy_from_slice(&buf[off + DIRENT_HEADER_SZ .. off + DIRENT_HEADER_SZ + n]);
+    Dirent { inode, entry_length, name_length, file_type, name }
+}
+
+/// Encode `d`'s header and active name bytes into `buf` at `off`. Slack space
+/// beyond the active name is left untouched.
+fn encode_dirent(buf: &mut [u8], off: usize, d: &Dirent) {
+    buf[off .. off + 4].copy_from_slice(&d.inode.to_le_bytes());
+    buf[off + 4 .. off + 6].copy_from_slice(&d.entry_length.to_le_bytes());
+    buf[off + 6] = d.name_length;
+    buf[off + 7] = d.file_type;
+    let n = d.name_length as usize;
+    buf[off + DIRENT_HEADER_SZ .. off + DIRENT_HEADER_SZ + n].copy_from_slice(&d.name[.. n]);
+}
+
+/// Scan one directory block for a slot with at least `needed` bytes of slack
+/// (a hole, or an active entry with more length than its content requires)
+/// and split it to hold `(ino, name, file_type)`. Returns whether it fit.
+fn try_insert(buf: &mut [u8; BLOCK_SZ], name: &[u8], ino: u32, file_type: u8, needed: usize) -> bool {
+    let mut off = 0;
+    while off < BLOCK_SZ {
+        let d = decode_dirent(buf, off);
+        if d.entry_length == 0 {
+            break;
+        }
+        let used = if d.inode == 0 { 0 } else { align4(DIRENT_HEADER_SZ + d.name_length as usize) };
+        let slack = d.entry_length as usize - used;
+        if slack >= needed {
+            let new_entry = Dirent {
+                inode: ino,
+                entry_length: (d.entry_length as usize - used) as u16,
+                name_length: name.len() as u8,
+                file_type,
+                name: padded_name(name),
+            };
+            if used > 0 {
+                let mut shrunk = decode_dirent(buf, off);
+                shrunk.entry_length = used as u16;
+                encode_dirent(buf, off, &shrunk);
+                encode_dirent(buf, off + used, &new_entry);
+            } else {
+                encode_dirent(buf, off, &new_entry);
+            }
+            return true;
+        }
+        off += d.entry_length as usize;
+    }
+    false
+}
+
+impl Ext2Fs {
+    /// Scan `dir_inode`'s data blocks for `name`, returning its inode number.
+    pub fn dir_lookup(&mut self, dir_inode: &Inode, name: &str) -> Option<u32> {
+        let name = name.as_bytes();
+        let nblocks = (dir_inode.i_size as usize).div_ceil(BLOCK_SZ);
+        for logical in 0 .. nblocks {
+            let Some(bid) = self.block_map(dir_inode, logical) else { continue };
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, bid as usize);
+            let mut off = 0;
+            while off < BLOCK_SZ {
+                let d = decode_dirent(&buf, off);
+                if d.entry_length == 0 {
+                    break;
+                }
+                if d.inode != 0 && d.name_length as usize == name.len() && &d.name[.. name.len()] == name {
+                    return Some(d.inode);
+                }
+                off += d.entry_length as usize;
+            }
+        }
+        None
+    }
+
+    /// Insert `(name, ino, file_type)` into `dir_inode`, splitting a slot with
+    /// enough slack, or growing the directory with `balloc` when full.
+    pub fn dir_add(&mut self, dir_inode: &mut Inode, name: &str, ino: u32, file_type: u8) -> Option<()> {
+        let name_bytes = name.as_bytes();
+        assert!(name_bytes.len() <= NAME_LEN);
+        let needed = align4(DIRENT_HEADER_SZ + name_bytes.len());
+        let nblocks = (dir_inode.i_size as usize).div_ceil(BLOCK_SZ);
+
+        for logical in 0 .. nblocks {
+            let Some(bid) = self.block_map(dir_inode, logical) else { continue };
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, bid as usize);
+            if try_insert(&mut buf, name_bytes, ino, file_type, needed) {
+                self.bwrite(&buf, bid as usize);
+                return Some(());
+            }
+        }
+
+        // No existing slot had room; grow the directory by one block.
+        let logical = nblocks;
+        let bid = self.block_map_alloc(dir_inode, logical)?;
+        dir_inode.i_size = ((logical + 1) * BLOCK_SZ) as u64;
+        let mut buf = [0u8; BLOCK_SZ];
+        encode_dirent(&mut buf, 0, &Dirent { inode: 0, entry_length: BLOCK_SZ as u16, name_length: 0, file_type: 0, name: [0; NAME_LEN] });
+        if !try_insert(&mut buf, name_bytes, ino, file_type, needed) {
+            return None;
+        }
+        self.bwrite(&buf, bid as usize);
+        Some(())
+    }
+
+    /// Remove `name` from `dir_inode`, merging the freed record into its
+    /// predecessor's `entry_length` (or marking it a hole if it was first).
+    pub fn dir_remove(&mut self, dir_inode: &Inode, name: &str) -> bool {
+        let name = name.as_bytes();
+        let nblocks = (dir_inode.i_size as usize).div_ceil(BLOCK_SZ);
+        for logical in 0 .. nblocks {
+            let Some(bid) = self.block_map(dir_inode, logical) else { continue };
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, bid as usize);
+            let mut prev_off = None;
+            let mut off = 0;
+            while off < BLOCK_SZ {
+                let d = decode_dirent(&buf, off);
+                if d.entry_length == 0 {
+                    break;
+                }
+                if d.inode != 0 && d.name_length as usize == name.len() && &d.name[.. name.len()] == name {
+                    if let Some(p) = prev_off {
+                        let mut prev: Dirent = decode_dirent(&buf, p);
+                        prev.entry_length += d.entry_length;
+                        encode_dirent(&mut buf, p, &prev);
+                    } else {
+                        let mut hole = d;
+                        hole.inode = 0;
+                        encode_dirent(&mut buf, off, &hole);
+                    }
+                    self.bwrite(&buf, bid as usize);
+                    return true;
+                }
+                prev_off = Some(off);
+                off += d.entry_length as usize;
+            }
+        }
+        false
+    }
+
+    /// Materialize every live entry of `dir_inode` as `(inode, file_type, name)`.
+    pub fn dir_entries(&mut self, dir_inode: &Inode) -> Vec<(u32, u8, String)> {
+        let mut out = Vec::new();
+        let nblocks = (dir_inode.i_size as usize).div_ceil(BLOCK_SZ);
+        for logical in 0 .. nblocks {
+            let Some(bid) = self.block_map(dir_inode, logical) else { continue };
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, bid as usize);
+            let mut off = 0;
+            while off < BLOCK_SZ {
+                let d = decode_dirent(&buf, off);
+                if d.entry_length == 0 {
+                    break;
+                }
+                if d.inode != 0 {
+                    let name = String::from_utf8_lossy(&d.name[.. d.name_length as usize]).into_owned();
+                    out.push((d.inode, d.file_type, name));
+                }
+                off += d.entry_length as usize;
+            }
+        }
+        out
+    }
+
+    /// Resolve an absolute path (e.g. `/a/b`) to an inode number by splitting
+    /// on `/` and walking directory entries from the root. When `follow_symlinks`
+    /// is set, each resolved component is followed through symlinks (bounded by
+    /// `MAX_SYMLINK_HOPS` to reject cycles).
+    pub fn namei(&mut self, path: &str, follow_symlinks: bool) -> Option<u32> {
+        let mut hops = 0;
+        self.namei_hops(path, follow_symlinks, &mut hops)
+    }
+
+    /// `namei` with a hop count shared across nested symlink resolutions.
+    fn namei_hops(&mut self, path: &str, follow_symlinks: bool, hops: &mut u32) -> Option<u32> {
+        let mut cur = ROOT_INO as u32;
+        for component in path.split('/').filter(|s| !s.is_empty()) {
+            let dir = self.read_inode(cur as u16);
+            cur = self.dir_lookup(&dir, component)?;
+            if follow_symlinks {
+                cur = self.resolve_symlink(cur, hops)?;
+            }
+        }
+        Some(cur)
+    }
+
+    /// Follow `ino` through symlinks, counting every link followed (including
+    /// those inside nested targets) in `hops`, returning the first non-symlink
+    /// inode number reached, or `None` once `MAX_SYMLINK_HOPS` is exceeded.
+    fn resolve_symlink(&mut self, mut ino: u32, hops: &mut u32) -> Option<u32> {
+        loop {
+            let ino16 = u16::try_from(ino).ok()?;
+            let inode = self.read_inode(ino16);
+            if !inode.is_symlink() {
+                return Some(ino);
+            }
+            if *hops >= MAX_SYMLINK_HOPS {
+                return None;
+            }
+            *hops += 1;
+            let target = self.readlink(&inode);
+            ino = self.namei_hops(&target, true, hops)?;
+        }
+    }
+
+    /// Create a regular file `name` in `dir_ino`, owned by `uid`/`gid` with
+    /// permission bits `perm`. Requires write+execute access to the directory.
+    pub fn create(&mut self, dir_ino: u16, name: &str, uid: u32, gid: u32, perm: u16) -> Option<u32> {
+        let mut dir_inode = self.read_inode(dir_ino);
+        if !access(&dir_inode, uid, gid, W_OK | X_OK) || self.dir_lookup(&dir_inode, name).is_some() {
+            return None;
+        }
+        let ino = self.ialloc()?;
+        let file = Inode::new_file(now(), perm, uid, gid);
+        self.write_inode(ino, &file);
+        self.dir_add(&mut dir_inode, name, ino as u32, FT_REG_FILE)?;
+        self.write_inode(dir_ino, &dir_inode);
+        Some(ino as u32)
+    }
+
+    /// Create a subdirectory `name` in `dir_ino`, owned by `uid`/`gid`, with
+    /// the usual `.`/`..` entries. Bumps `dir_ino`'s link count for the new
+    /// `..` pointing back at it. Requires write+execute access to the parent.
+    pub fn mkdir(&mut self, dir_ino: u16, name: &str, uid: u32, gid: u32) -> Option<u32> {
+        let mut dir_inode = self.read_inode(dir_ino);
+        if !access(&dir_inode, uid, gid, W_OK | X_OK) || self.dir_lookup(&dir_inode, name).is_some() {
+            return None;
+        }
+        let ino = self.ialloc()?;
+        let bid = self.balloc()?;
+        let mut child = Inode::new_dir(now(), 0u32, bid, uid, gid);
+        self.dir_add(&mut child, ".", ino as u32, FT_DIR)?;
+        self.dir_add(&mut child, "..", dir_ino as u32, FT_DIR)?;
+        self.write_inode(ino, &child);
+        self.dir_add(&mut dir_inode, name, ino as u32, FT_DIR)?;
+        dir_inode.i_links_count += 1;
+        self.write_inode(dir_ino, &dir_inode);
+        Some(ino as u32)
+    }
+
+    /// Remove `name` from `dir_ino`, freeing its inode once the link count
+    /// reaches zero. Requires write+execute access to the parent directory.
+    pub fn unlink(&mut self, dir_ino: u16, name: &str, uid: u32, gid: u32) -> bool {
+        let dir_inode = self.read_inode(dir_ino);
+        if !access(&dir_inode, uid, gid, W_OK | X_OK) {
+            return false;
+        }
+        let Some(ino) = self.dir_lookup(&dir_inode, name).filter(|&ino| ino <= u16::MAX as u32) else { return false };
+        if !self.dir_remove(&dir_inode, name) {
+            return false;
+        }
+        let mut inode = self.read_inode(ino as u16);
+        inode.i_links_count = inode.i_links_count.saturating_sub(1);
+        if inode.i_links_count == 0 {
+            // an inline ("fast") symlink has no real blocks to free: its
+            // i_block holds packed target bytes, not block ids
+            if !(inode.is_symlink() && inode.i_blocks == 0) {
+                self.free_blocks(&inode);
+            }
+            self.ifree(ino as u16);
+        } else {
+            self.write_inode(ino as u16, &inode);
+        }
+        true
+    }
+
+    /// Create a symlink `name` in `dir_ino` pointing at `target`, owned by
+    /// `uid`/`gid`. Uses the ext2 "fast symlink" optimization: a target that
+    /// fits inline in `i_block` is stored there by `Inode::new_symlink`; a
+    /// longer one is written to a freshly `balloc`'d data block instead.
+    pub fn symlink(&mut self, dir_ino: u16, name: &str, target: &str, uid: u32, gid: u32) -> Option<u32> {
+        if target.len() > BLOCK_SZ {
+            return None;
+        }
+        let mut dir_inode = self.read_inode(dir_ino);
+        if !access(&dir_inode, uid, gid, W_OK | X_OK) || self.dir_lookup(&dir_inode, name).is_some() {
+            return None;
+        }
+        let ino = self.ialloc()?;
+        let mut link = Inode::new_symlink(now(), target);
+        link.i_uid = uid;
+        link.i_gid = gid;
+        if target.len() > N_BLOCKS * 2 {
+            let bid = self.balloc()?;
+            let mut buf = [0u8; BLOCK_SZ];
+            buf[.. target.len()].copy_from_slice(target.as_bytes());
+            self.bwrite(&buf, bid as usize);
+            link.i_block[0] = bid;
+            link.i_blocks = 1;
+        }
+        self.write_inode(ino, &link);
+        self.dir_add(&mut dir_inode, name, ino as u32, FT_SYMLINK)?;
+        self.write_inode(dir_ino, &dir_inode);
+        Some(ino as u32)
+    }
+
+    /// Read the target path of a symlink `inode`: inline from `i_block` when
+    /// `i_blocks == 0`, or from its data block otherwise.
+    pub fn readlink(&mut self, inode: &Inode) -> String {
+        let size = inode.i_size as usize;
+        let bytes = if inode.i_blocks == 0 {
+            let mut bytes = Vec::with_capacity(N_BLOCKS * 2);
+            for &word in &inode.i_block {
+                bytes.extend_from_slice(&word.to_le_bytes());
+            }
+            bytes
+        } else {
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, inode.i_block[0] as usize);
+            buf.to_vec()
+        };
+        String::from_utf8_lossy(&bytes[.. size.min(bytes.len())]).into_owned()
+    }
+}
@@ -1,6 +1,8 @@
 use std::mem;
 use crate::fs::bio::BlockDev;
-use crate::fs::inode::Inode;
+use crate::fs::cache::BlockCache;
+use crate::fs::inode::{ Inode, N_DIR_BLOCKS, IND_BLOCK, DIND_BLOCK, TIND_BLOCK };
+use crate::fs::file::FT_DIR;
 use mem::size_of;
 use mem::transmute;
 use std::slice;
@@ -10,8 +12,12 @@ use crate::fs::ext2::Error::FormatError;
 use anyhow::Result;
 use thiserror::Error;
 
-const BLOCK_SZ: usize = 1024;   // 1 KB
-const MAX_FILE_COUNT: usize = 1024;
+pub(crate) const BLOCK_SZ: usize = 1024;   // 1 KB
+const PTRS_PER_BLOCK: usize = BLOCK_SZ / size_of::<u16>();   // block ids per indirect block
+const CACHE_CAPACITY: usize = 64;   // blocks held by the write-back cache
+// Inodes are never packed across a block boundary, so a block holds only as
+// many whole inodes as fit; the remainder is unused padding.
+const INODES_PER_BLOCK: usize = BLOCK_SZ / size_of::<Inode>();
 const SUPER_BLOCK: usize = 0;
 const SUPER_BLOCK_NUM: usize = 1;
 const FREE_BITMAP_BLOCK: usize = SUPER_BLOCK + SUPER_BLOCK_NUM;
@@ -20,6 +26,7 @@ const INODE_BITMAP_BLOCK: usize = FREE_BITMAP_BLOCK + FREE_BITMAP_BLOCK_SZ;
 const INODE_BITMAP_BLOCK_NUM: usize = 1;
 const INODE_TABLE_BLOCKS: usize = INODE_BITMAP_BLOCK + INODE_BITMAP_BLOCK_NUM;
 const INODE_TABLE_BLOCKS_SZ: usize = 60;
+const MAX_FILE_COUNT: usize = INODE_TABLE_BLOCKS_SZ * INODES_PER_BLOCK;
 const DATA_BLOCKS: usize = INODE_TABLE_BLOCKS + INODE_TABLE_BLOCKS_SZ;
 const META_BLOCKS_SZ: usize = DATA_BLOCKS;
 
@@ -30,7 +37,7 @@ macro_rules! word_set_at {
 
 #[allow(unused)]
 macro_rules! word_clear_at {
-    ($word: expr, $index: expr) => { ($word) &= ~(1u64 << (63 - ($index))) };
+    ($word: expr, $index: expr) => { ($word) &= !(1u64 << (63 - ($index))) };
 }
 
 #[allow(unused)]
@@ -56,12 +63,20 @@ enum Error {
 
 
 
-struct Ext2Fs {
+/// Inode number of the root directory. Inode 0 is deliberately left
+/// unallocated: dir.rs treats `inode == 0` as its "free slot" sentinel for
+/// holes and removed entries, so using it as a real inode would make root's
+/// `..` indistinguishable from a hole. Inode 1 is reserved too, matching
+/// ext2's traditional bad-blocks inode; root becomes inode 2.
+pub const ROOT_INO: u16 = 2;
+
+/// Raw, uncached view of the disk image; the bottom of the `BlockCache` stack.
+struct RawImage {
     image: Box<[u8]>,
 }
 
-impl BlockDev for Ext2Fs {
-    fn bread(&self, buf: &mut [u8], bid: usize) {
+impl BlockDev for RawImage {
+    fn bread(&mut self, buf: &mut [u8], bid: usize) {
         buf.copy_from_slice(&self.image[one_block_from!(bid)]);
     }
 
@@ -70,43 +85,311 @@ impl BlockDev for Ext2Fs {
     }
 }
 
+pub struct Ext2Fs {
+    cache: BlockCache<RawImage>,
+    block_count: usize,
+}
+
+impl BlockDev for Ext2Fs {
+    fn bread(&mut self, buf: &mut [u8], bid: usize) {
+        self.cache.bread(buf, bid);
+    }
+
+    fn bwrite(&mut self, buf: &[u8], bid: usize) {
+        self.cache.bwrite(buf, bid);
+    }
+}
+
 unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
     slice::from_raw_parts((p as *const T) as *const u8, size_of::<T>())
 }
 
 impl Ext2Fs {
     pub fn new(image: Box<[u8]>) -> Ext2Fs {
-        Ext2Fs{ image }
+        let block_count = image.len() / BLOCK_SZ;
+        let cache = BlockCache::new(RawImage { image }, BLOCK_SZ, CACHE_CAPACITY);
+        Ext2Fs { cache, block_count }
     }
 
-    /// Find the first 0 bit in bitmap block specified by bitmap_bid and set it to 1.
-    /// Return bit offset from 0th bit if found, or None if not found.
+    /// Flush every dirty cached block back to the underlying image.
+    pub fn sync(&mut self) {
+        self.cache.sync();
+    }
+
+    /// Find the first 0 bit in bitmap block specified by bitmap_bid, set it to 1
+    /// and write the bitmap block back. Return bit offset from 0th bit if found,
+    /// or None if the bitmap is exhausted.
     fn first_match(&mut self, bitmap_bid: usize) -> Option<usize> {
         let mut buf = [0u8; BLOCK_SZ];
         self.bread(&mut buf, bitmap_bid);
-        let block_ref: &mut [u64; BLOCK_SZ / 8] = unsafe { transmute(&mut buf) };
-        // find a word that is not all 1, and find the position of first 0 bit in the word.
-        if let Some((word_idx, mut word)) = block_ref.iter_mut().enumerate().find(|(_, &mut x)| x != u64::MAX) {
-            word_set_at!(*word, word.leading_ones());
-            return Some(word_idx * 64 + word.leading_ones() as usize);
+        let idx = {
+            let block_ref: &mut [u64; BLOCK_SZ / 8] = unsafe { transmute(&mut buf) };
+            // find a word that is not all 1, and find the position of first 0 bit in the word.
+            let (word_idx, word) = block_ref.iter_mut().enumerate().find(|(_, &mut x)| x != u64::MAX)?;
+            let bit = word.leading_ones();
+            word_set_at!(*word, bit);
+            word_idx * 64 + bit as usize
+        };
+        self.bwrite(&buf, bitmap_bid);
+        Some(idx)
+    }
+
+    /// Clear bit `index` of the bitmap block specified by `bitmap_bid` and write it back.
+    fn clear_bit(&mut self, bitmap_bid: usize, index: usize) {
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, bitmap_bid);
+        {
+            let block_ref: &mut [u64; BLOCK_SZ / 8] = unsafe { transmute(&mut buf) };
+            word_clear_at!(block_ref[index / 64], index % 64);
         }
-        None
+        self.bwrite(&buf, bitmap_bid);
     }
 
-    /// Allocate a free inode using first match algorithm
-    /// Return an inode number as u16 on success, None on failure
-    fn ialloc(&mut self) -> Option<u16> {
+    /// Read the on-disk super block.
+    fn read_super(&mut self) -> SuperBlk {
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, SUPER_BLOCK);
+        let mut sb: SuperBlk = unsafe { mem::zeroed() };
+        let bytes = unsafe { slice::from_raw_parts_mut((&mut sb as *mut SuperBlk) as *mut u8, size_of::<SuperBlk>()) };
+        bytes.copy_from_slice(&buf[.. size_of::<SuperBlk>()]);
+        sb
+    }
+
+    /// Write `sb` back to the on-disk super block.
+    fn write_super(&mut self, sb: &SuperBlk) {
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, SUPER_BLOCK);
+        buf[.. size_of::<SuperBlk>()].copy_from_slice(unsafe { any_as_u8_slice(sb) });
+        self.bwrite(&buf, SUPER_BLOCK);
+    }
+
+    /// Allocate a free inode using first match algorithm.
+    /// Return an inode number as u16 on success, None if the inode bitmap is exhausted.
+    pub(crate) fn ialloc(&mut self) -> Option<u16> {
         let i = self.first_match(INODE_BITMAP_BLOCK)?;
+        let mut sb = self.read_super();
+        sb.s_free_inodes_count -= 1;
+        self.write_super(&sb);
         Some(i as u16)
     }
 
-    /// Allocate a free data block using first match algorithm
-    /// Return a block id as u16 on success, None on failure
-    fn balloc(&mut self) -> Option<u16> {
+    /// Release inode `ino` back to the inode bitmap.
+    pub(crate) fn ifree(&mut self, ino: u16) {
+        self.clear_bit(INODE_BITMAP_BLOCK, ino as usize);
+        let mut sb = self.read_super();
+        sb.s_free_inodes_count += 1;
+        self.write_super(&sb);
+    }
+
+    /// Allocate a free data block using first match algorithm.
+    /// Return a block id as u16 on success, None if the block bitmap is exhausted.
+    pub(crate) fn balloc(&mut self) -> Option<u16> {
         let bid = self.first_match(FREE_BITMAP_BLOCK)?;
+        let mut sb = self.read_super();
+        sb.s_free_blocks_count -= 1;
+        self.write_super(&sb);
         Some(bid as u16)
     }
 
+    /// Release data block `bid` back to the free block bitmap.
+    fn bfree(&mut self, bid: u16) {
+        self.clear_bit(FREE_BITMAP_BLOCK, bid as usize);
+        let mut sb = self.read_super();
+        sb.s_free_blocks_count += 1;
+        self.write_super(&sb);
+    }
+
+    /// Free an index block chain `depth` levels deep rooted at `bid` (0 means
+    /// `bid` is itself a data block), including `bid`. A hole (`bid == 0`) is a
+    /// no-op.
+    fn free_indirect(&mut self, bid: u16, depth: usize) {
+        if bid == 0 {
+            return;
+        }
+        if depth > 0 {
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, bid as usize);
+            let ptrs: [u16; PTRS_PER_BLOCK] = unsafe { transmute(buf) };
+            for ptr in ptrs {
+                self.free_indirect(ptr, depth - 1);
+            }
+        }
+        self.bfree(bid);
+    }
+
+    /// Free every data and index block allocated to `inode` (direct entries,
+    /// then the single/double/triple indirect chains), via `bfree`. Must not be
+    /// called on an inline ("fast") symlink, whose `i_block` holds packed
+    /// target bytes rather than block ids.
+    pub(crate) fn free_blocks(&mut self, inode: &Inode) {
+        for &bid in &inode.i_block[.. N_DIR_BLOCKS] {
+            if bid != 0 {
+                self.bfree(bid);
+            }
+        }
+        self.free_indirect(inode.i_block[IND_BLOCK], 1);
+        self.free_indirect(inode.i_block[DIND_BLOCK], 2);
+        self.free_indirect(inode.i_block[TIND_BLOCK], 3);
+    }
+
+    /// Look up entry `idx` of the indirect block `bid`. Returns None if the
+    /// indirect block itself is unallocated or the entry is a hole.
+    fn indirect_lookup(&mut self, bid: u16, idx: usize) -> Option<u16> {
+        if bid == 0 {
+            return None;
+        }
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, bid as usize);
+        let ptrs: &[u16; PTRS_PER_BLOCK] = unsafe { transmute(&buf) };
+        Some(ptrs[idx]).filter(|&v| v != 0)
+    }
+
+    /// Resolve a logical block index within an inode to a physical block id by
+    /// walking direct entries, then single-, double- and triple-indirect
+    /// blocks. Returns None for holes or an unallocated chain.
+    pub(crate) fn block_map(&mut self, inode: &Inode, logical: usize) -> Option<u16> {
+        if logical < N_DIR_BLOCKS {
+            return Some(inode.i_block[logical]).filter(|&b| b != 0);
+        }
+        let logical = logical - N_DIR_BLOCKS;
+        if logical < PTRS_PER_BLOCK {
+            return self.indirect_lookup(inode.i_block[IND_BLOCK], logical);
+        }
+        let logical = logical - PTRS_PER_BLOCK;
+        if logical < PTRS_PER_BLOCK * PTRS_PER_BLOCK {
+            let l1 = self.indirect_lookup(inode.i_block[DIND_BLOCK], logical / PTRS_PER_BLOCK)?;
+            return self.indirect_lookup(l1, logical % PTRS_PER_BLOCK);
+        }
+        let logical = logical - PTRS_PER_BLOCK * PTRS_PER_BLOCK;
+        let l1 = self.indirect_lookup(inode.i_block[TIND_BLOCK], logical / (PTRS_PER_BLOCK * PTRS_PER_BLOCK))?;
+        let l2 = self.indirect_lookup(l1, (logical / PTRS_PER_BLOCK) % PTRS_PER_BLOCK)?;
+        self.indirect_lookup(l2, logical % PTRS_PER_BLOCK)
+    }
+
+    /// Allocate `inode.i_block[idx]` if it is currently a hole, counting the new
+    /// block in `i_blocks`, then return it.
+    fn ensure_block(&mut self, inode: &mut Inode, idx: usize) -> Option<u16> {
+        if inode.i_block[idx] == 0 {
+            let bid = self.balloc()?;
+            let zero = [0u8; BLOCK_SZ];
+            self.bwrite(&zero, bid as usize);
+            inode.i_block[idx] = bid;
+            inode.i_blocks += 1;
+        }
+        Some(inode.i_block[idx])
+    }
+
+    /// Like `ensure_block`, but the slot lives at entry `idx` of indirect block
+    /// `bid`; a newly allocated entry is also counted in `inode.i_blocks`.
+    fn ensure_indirect_entry(&mut self, inode: &mut Inode, bid: u16, idx: usize) -> Option<u16> {
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, bid as usize);
+        let ptrs: &mut [u16; PTRS_PER_BLOCK] = unsafe { transmute(&mut buf) };
+        if ptrs[idx] == 0 {
+            ptrs[idx] = self.balloc()?;
+            self.bwrite(&buf, bid as usize);
+            let zero = [0u8; BLOCK_SZ];
+            self.bwrite(&zero, ptrs[idx] as usize);
+            inode.i_blocks += 1;
+        }
+        Some(ptrs[idx])
+    }
+
+    /// Like `block_map`, but allocates direct blocks and indirect chains on
+    /// demand instead of reporting holes. Every data and index block handed
+    /// out this way is counted in `inode.i_blocks`.
+    pub(crate) fn block_map_alloc(&mut self, inode: &mut Inode, logical: usize) -> Option<u16> {
+        if logical < N_DIR_BLOCKS {
+            return self.ensure_block(inode, logical);
+        }
+        let logical = logical - N_DIR_BLOCKS;
+        if logical < PTRS_PER_BLOCK {
+            let ind = self.ensure_block(inode, IND_BLOCK)?;
+            return self.ensure_indirect_entry(inode, ind, logical);
+        }
+        let logical = logical - PTRS_PER_BLOCK;
+        if logical < PTRS_PER_BLOCK * PTRS_PER_BLOCK {
+            let dind = self.ensure_block(inode, DIND_BLOCK)?;
+            let l1 = self.ensure_indirect_entry(inode, dind, logical / PTRS_PER_BLOCK)?;
+            return self.ensure_indirect_entry(inode, l1, logical % PTRS_PER_BLOCK);
+        }
+        let logical = logical - PTRS_PER_BLOCK * PTRS_PER_BLOCK;
+        let tind = self.ensure_block(inode, TIND_BLOCK)?;
+        let l1 = self.ensure_indirect_entry(inode, tind, logical / (PTRS_PER_BLOCK * PTRS_PER_BLOCK))?;
+        let l2 = self.ensure_indirect_entry(inode, l1, (logical / PTRS_PER_BLOCK) % PTRS_PER_BLOCK)?;
+        self.ensure_indirect_entry(inode, l2, logical % PTRS_PER_BLOCK)
+    }
+
+    /// Block id and byte offset within that block of the on-disk slot for
+    /// `ino`. Inodes are packed `INODES_PER_BLOCK` to a block, never straddling
+    /// a block boundary, so this never needs to span two reads.
+    fn inode_location(ino: u16) -> (usize, usize) {
+        let ino = ino as usize;
+        (INODE_TABLE_BLOCKS + ino / INODES_PER_BLOCK, (ino % INODES_PER_BLOCK) * size_of::<Inode>())
+    }
+
+    /// Read the on-disk inode numbered `ino`.
+    pub fn read_inode(&mut self, ino: u16) -> Inode {
+        let (bid, off) = Self::inode_location(ino);
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, bid);
+        let mut inode: Inode = unsafe { mem::zeroed() };
+        let bytes = unsafe { slice::from_raw_parts_mut((&mut inode as *mut Inode) as *mut u8, size_of::<Inode>()) };
+        bytes.copy_from_slice(&buf[off .. off + size_of::<Inode>()]);
+        inode
+    }
+
+    /// Write `inode` back to its on-disk slot numbered `ino`.
+    pub fn write_inode(&mut self, ino: u16, inode: &Inode) {
+        let (bid, off) = Self::inode_location(ino);
+        let mut buf = [0u8; BLOCK_SZ];
+        self.bread(&mut buf, bid);
+        buf[off .. off + size_of::<Inode>()].copy_from_slice(unsafe { any_as_u8_slice(inode) });
+        self.bwrite(&buf, bid);
+    }
+
+    /// Read up to `size` bytes of file content starting at `offset`, zero-padding
+    /// over holes in the direct/indirect block chain.
+    pub fn read_file(&mut self, inode: &Inode, offset: usize, size: usize) -> Vec<u8> {
+        let avail = (inode.i_size as usize).saturating_sub(offset);
+        let mut remaining = size.min(avail);
+        let mut out = Vec::with_capacity(remaining);
+        let mut pos = offset;
+        while remaining > 0 {
+            let logical = pos / BLOCK_SZ;
+            let in_block = pos % BLOCK_SZ;
+            let take = remaining.min(BLOCK_SZ - in_block);
+            let mut buf = [0u8; BLOCK_SZ];
+            if let Some(bid) = self.block_map(inode, logical) {
+                self.bread(&mut buf, bid as usize);
+            }
+            out.extend_from_slice(&buf[in_block .. in_block + take]);
+            pos += take;
+            remaining -= take;
+        }
+        out
+    }
+
+    /// Write `data` into `inode` starting at `offset`, allocating direct and
+    /// indirect blocks on demand and growing `i_size`.
+    pub fn write_file(&mut self, inode: &mut Inode, offset: usize, data: &[u8]) {
+        let mut pos = offset;
+        let mut written = 0;
+        while written < data.len() {
+            let logical = pos / BLOCK_SZ;
+            let in_block = pos % BLOCK_SZ;
+            let Some(bid) = self.block_map_alloc(inode, logical) else { break };
+            let take = (data.len() - written).min(BLOCK_SZ - in_block);
+            let mut buf = [0u8; BLOCK_SZ];
+            self.bread(&mut buf, bid as usize);
+            buf[in_block .. in_block + take].copy_from_slice(&data[written .. written + take]);
+            self.bwrite(&buf, bid as usize);
+            pos += take;
+            written += take;
+        }
+        inode.i_size = inode.i_size.max(pos as u64);
+    }
+
     /// Format the disk image to Ext2 Filesystem.
     /// Super Block:       1 block
     /// Free Bitmap:       1 blocks
@@ -114,15 +397,15 @@ impl Ext2Fs {
     /// Inode Table:       64 blocks
     /// Data:              remaining blocks
     ///
-    /// Root directory is allocated as the first inode initially
+    /// Root directory is allocated as inode `ROOT_INO` (inodes 0 and 1 are reserved)
     pub fn format(&mut self) -> Result<()> {
-        let image_size = self.image.len();
-        let block_count = image_size / BLOCK_SZ;
+        let block_count = self.block_count;
         let super_blk = SuperBlk {
             s_inodes_count: 1,
             s_blocks_count: block_count as u16,
             s_free_blocks_count: (block_count - META_BLOCKS_SZ - 1) as u16,
-            s_free_inodes_count: (MAX_FILE_COUNT - 1) as u16,
+            // inodes 0 and 1 are reserved (see ROOT_INO) and never handed out by ialloc
+            s_free_inodes_count: (MAX_FILE_COUNT - 2) as u16,
             s_first_data_block: META_BLOCKS_SZ as u16,
             s_block_size: BLOCK_SZ as u16,
             s_last_allocate: (META_BLOCKS_SZ + 1) as u16,
@@ -135,11 +418,31 @@ impl Ext2Fs {
         // writing super block
         self.bwrite(&super_block, SUPER_BLOCK);
 
-        let free_bitmap = [0u8; BLOCK_SZ];
+        // mark the meta region (super block, bitmaps, inode table) as
+        // already allocated so balloc() only ever hands out data blocks
+        let mut free_bitmap = [0u8; BLOCK_SZ];
+        {
+            let words: &mut [u64; BLOCK_SZ / 8] = unsafe { transmute(&mut free_bitmap) };
+            for bit in 0 .. DATA_BLOCKS {
+                word_set_at!(words[bit / 64], bit % 64);
+            }
+        }
         // writing free bitmap block
         self.bwrite(&free_bitmap, FREE_BITMAP_BLOCK);
 
-        let inode_bitmap = [0u8; BLOCK_SZ];
+        // reserve inodes 0 and 1 up front (see ROOT_INO) so ialloc never hands
+        // either of them out, and mark every bit past MAX_FILE_COUNT as used
+        // too so ialloc never hands out an inode number with no slot in the
+        // (fixed-size) inode table
+        let mut inode_bitmap = [0u8; BLOCK_SZ];
+        {
+            let words: &mut [u64; BLOCK_SZ / 8] = unsafe { transmute(&mut inode_bitmap) };
+            word_set_at!(words[0], 0);
+            word_set_at!(words[0], 1);
+            for bit in MAX_FILE_COUNT .. BLOCK_SZ * 8 {
+                word_set_at!(words[bit / 64], bit % 64);
+            }
+        }
         // writing inode bitmap block
         self.bwrite(&inode_bitmap, INODE_BITMAP_BLOCK);
 
@@ -152,11 +455,11 @@ impl Ext2Fs {
         let ino = self.ialloc().ok_or(FormatError(1))?;
         // allocate the first data block for root directory
         let bid = self.balloc().ok_or(FormatError(2))?;
-        let inode = Inode::new_dir(time, 0u32, bid);
-        let mut inode_block = [0u8; BLOCK_SZ];
-        inode_block[ino as usize * size_of::<Inode>() .. (ino as usize + 1) * size_of::<Inode>()]
-            .copy_from_slice( unsafe { transmute::<&Inode, &[u8; size_of::<Inode>()]>(&inode)} );
-        self.bwrite(&inode_block, bid.into());
+        let mut inode = Inode::new_dir(time, 0u32, bid, 0, 0);
+        // root has no parent, so both "." and ".." point back at itself
+        self.dir_add(&mut inode, ".", ino as u32, FT_DIR).ok_or(FormatError(3))?;
+        self.dir_add(&mut inode, "..", ino as u32, FT_DIR).ok_or(FormatError(3))?;
+        self.write_inode(ino, &inode);
         Ok(())
     }
 }
\ No newline at end of file
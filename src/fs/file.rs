@@ -1,8 +1,20 @@
-const NAME_LEN: usize = 255;
-struct Dirent {
-    inode: u32,
-    entry_length: u16,
-    name_length: u8,
-    file_type: u8,
-    name: [u8; NAME_LEN],
+pub(crate) const NAME_LEN: usize = 255;
+
+/// Values for `Dirent::file_type`, mirroring ext2's `EXT2_FT_*` constants.
+pub(crate) const FT_UNKNOWN: u8 = 0;
+pub(crate) const FT_REG_FILE: u8 = 1;
+pub(crate) const FT_DIR: u8 = 2;
+pub(crate) const FT_SYMLINK: u8 = 7;
+
+/// In-memory view of one directory entry record. On disk, a directory's data
+/// blocks hold these packed back-to-back: an 8-byte header (`inode`,
+/// `entry_length`, `name_length`, `file_type`) followed by `name_length`
+/// bytes of name, with `entry_length` rounding up to the next slot and
+/// chaining to the following record (or to slack space reserved for growth).
+pub(crate) struct Dirent {
+    pub(crate) inode: u32,
+    pub(crate) entry_length: u16,
+    pub(crate) name_length: u8,
+    pub(crate) file_type: u8,
+    pub(crate) name: [u8; NAME_LEN],
 }
\ No newline at end of file
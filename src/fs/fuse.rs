@@ -0,0 +1,156 @@
+use std::ffi::OsStr;
+use std::path::Path;
+use std::time::{ Duration, UNIX_EPOCH };
+
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, Request,
+    ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry, ReplyWrite,
+};
+use libc::{ EACCES, EIO, ENOENT };
+
+use anyhow::Result;
+
+use crate::fs::ext2::{ Ext2Fs, ROOT_INO };
+use crate::fs::file::{ FT_DIR, FT_SYMLINK };
+use crate::fs::inode::Inode;
+
+const TTL: Duration = Duration::from_secs(1);
+
+/// Adapts `Ext2Fs` to the `fuser::Filesystem` trait so an image can be mounted
+/// at a real mountpoint. FUSE inode numbers map one-to-one onto our inode
+/// table indices, offset by `ROOT_INO - 1` (see `to_internal_ino`), and FUSE byte offsets map onto `i_block` slots of `BLOCK_SZ` bytes.
+pub struct FuseAdapter {
+    fs: Ext2Fs,
+}
+
+impl FuseAdapter {
+    pub fn new(fs: Ext2Fs) -> FuseAdapter {
+        FuseAdapter { fs }
+    }
+
+    /// FUSE reserves inode 1 for the mount root; our table's root lives at
+    /// `ROOT_INO`, so fuse inode numbers are shifted by `ROOT_INO - 1`.
+    fn to_internal_ino(fuse_ino: u64) -> Option<u16> {
+        u16::try_from(fuse_ino.checked_add(ROOT_INO as u64 - 1)?).ok()
+    }
+
+    fn to_fuse_ino(ino: u16) -> u64 {
+        ino as u64 - (ROOT_INO as u64 - 1)
+    }
+
+    fn attr_of(ino: u64, inode: &Inode) -> FileAttr {
+        let kind = if inode.i_mode & 0xF000 == 0x4000 { FileType::Directory }
+                   else if inode.i_mode & 0xF000 == 0xA000 { FileType::Symlink }
+                   else { FileType::RegularFile };
+        FileAttr {
+            ino,
+            size: inode.i_size,
+            blocks: inode.i_blocks as u64,
+            atime: UNIX_EPOCH + Duration::from_secs(inode.i_atime),
+            mtime: UNIX_EPOCH + Duration::from_secs(inode.i_mtime),
+            ctime: UNIX_EPOCH + Duration::from_secs(inode.i_ctime),
+            crtime: UNIX_EPOCH + Duration::from_secs(inode.i_ctime),
+            kind,
+            perm: (inode.i_mode & 0o777) as u16,
+            nlink: inode.i_links_count as u32,
+            uid: inode.i_uid,
+            gid: inode.i_gid,
+            rdev: 0,
+            blksize: 1024,
+            flags: 0,
+        }
+    }
+}
+
+impl Filesystem for FuseAdapter {
+    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let (Some(parent), Some(name)) = (Self::to_internal_ino(parent), name.to_str()) else { reply.error(ENOENT); return };
+        let dir = self.fs.read_inode(parent);
+        let Some(ino) = self.fs.dir_lookup(&dir, name).filter(|&ino| ino <= u16::MAX as u32) else { reply.error(ENOENT); return };
+        let inode = self.fs.read_inode(ino as u16);
+        reply.entry(&TTL, &Self::attr_of(Self::to_fuse_ino(ino as u16), &inode), 0);
+    }
+
+    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+        let Some(ino) = Self::to_internal_ino(ino) else { reply.error(ENOENT); return };
+        let inode = self.fs.read_inode(ino);
+        reply.attr(&TTL, &Self::attr_of(Self::to_fuse_ino(ino), &inode));
+    }
+
+    fn read(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, size: u32, _flags: i32, _lock: Option<u64>, reply: ReplyData) {
+        let (Some(ino), true) = (Self::to_internal_ino(ino), offset >= 0) else { reply.error(EIO); return };
+        let inode = self.fs.read_inode(ino);
+        let data = self.fs.read_file(&inode, offset as usize, size as usize);
+        reply.data(&data);
+    }
+
+    fn write(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, data: &[u8], _write_flags: u32, _flags: i32, _lock: Option<u64>, reply: ReplyWrite) {
+        let (Some(ino), true) = (Self::to_internal_ino(ino), offset >= 0) else { reply.error(EIO); return };
+        let mut inode = self.fs.read_inode(ino);
+        self.fs.write_file(&mut inode, offset as usize, data);
+        self.fs.write_inode(ino, &inode);
+        reply.written(data.len() as u32);
+    }
+
+    fn readdir(&mut self, _req: &Request, ino: u64, _fh: u64, offset: i64, mut reply: ReplyDirectory) {
+        let Some(ino) = Self::to_internal_ino(ino) else { reply.error(ENOENT); return };
+        let dir = self.fs.read_inode(ino);
+        let entries = self.fs.dir_entries(&dir);
+        for (i, (child_ino, file_type, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            let Ok(child_ino) = u16::try_from(child_ino) else { continue };
+            let kind = if file_type == FT_DIR { FileType::Directory }
+                       else if file_type == FT_SYMLINK { FileType::Symlink }
+                       else { FileType::RegularFile };
+            if reply.add(Self::to_fuse_ino(child_ino), (i + 1) as i64, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn create(&mut self, req: &Request, parent: u64, name: &OsStr, mode: u32, _umask: u32, _flags: i32, reply: fuser::ReplyCreate) {
+        let (Some(parent), Some(name)) = (Self::to_internal_ino(parent), name.to_str()) else { reply.error(ENOENT); return };
+        let Some(ino) = self.fs.create(parent, name, req.uid(), req.gid(), mode as u16).filter(|&ino| ino <= u16::MAX as u32) else { reply.error(EACCES); return };
+        let inode = self.fs.read_inode(ino as u16);
+        reply.created(&TTL, &Self::attr_of(Self::to_fuse_ino(ino as u16), &inode), 0, 0, 0);
+    }
+
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, _umask: u32, reply: ReplyEntry) {
+        let (Some(parent), Some(name)) = (Self::to_internal_ino(parent), name.to_str()) else { reply.error(ENOENT); return };
+        let Some(ino) = self.fs.mkdir(parent, name, req.uid(), req.gid()).filter(|&ino| ino <= u16::MAX as u32) else { reply.error(EACCES); return };
+        let inode = self.fs.read_inode(ino as u16);
+        reply.entry(&TTL, &Self::attr_of(Self::to_fuse_ino(ino as u16), &inode), 0);
+    }
+
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: fuser::ReplyEmpty) {
+        let (Some(parent), Some(name)) = (Self::to_internal_ino(parent), name.to_str()) else { reply.error(ENOENT); return };
+        if self.fs.unlink(parent, name, req.uid(), req.gid()) { reply.ok(); } else { reply.error(EACCES); }
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        let Some(ino) = Self::to_internal_ino(ino) else { reply.error(ENOENT); return };
+        let inode = self.fs.read_inode(ino);
+        reply.data(self.fs.readlink(&inode).as_bytes());
+    }
+
+    fn symlink(&mut self, req: &Request, parent: u64, link_name: &OsStr, target: &Path, reply: ReplyEntry) {
+        let (Some(parent), Some(name), Some(target)) = (Self::to_internal_ino(parent), link_name.to_str(), target.to_str()) else { reply.error(ENOENT); return };
+        let Some(ino) = self.fs.symlink(parent, name, target, req.uid(), req.gid()).filter(|&ino| ino <= u16::MAX as u32) else { reply.error(EACCES); return };
+        let inode = self.fs.read_inode(ino as u16);
+        reply.entry(&TTL, &Self::attr_of(Self::to_fuse_ino(ino as u16), &inode), 0);
+    }
+}
+
+/// Mount `image` at `mountpoint`, blocking until the filesystem is unmounted.
+pub fn mount(image: Box<[u8]>, mountpoint: &Path, auto_unmount: bool, allow_root: bool) -> Result<()> {
+    let fs = Ext2Fs::new(image);
+    let mut options = vec![MountOption::FSName("rustfs".to_string())];
+    if auto_unmount {
+        options.push(MountOption::AutoUnmount);
+    }
+    if allow_root {
+        options.push(MountOption::AllowRoot);
+    }
+    fuser::mount2(FuseAdapter::new(fs), mountpoint, &options)?;
+    Ok(())
+}
@@ -1,6 +1,11 @@
-const N_DIR_BLOCKS: usize = 10;
-const INDIR_BLOCK: usize = N_DIR_BLOCKS;
-const N_BLOCKS: usize = INDIR_BLOCK + 1;
+pub(crate) const N_DIR_BLOCKS: usize = 10;
+/// Index of the single-indirect block pointer within `i_block`.
+pub(crate) const IND_BLOCK: usize = N_DIR_BLOCKS;
+/// Index of the double-indirect block pointer within `i_block`.
+pub(crate) const DIND_BLOCK: usize = IND_BLOCK + 1;
+/// Index of the triple-indirect block pointer within `i_block`.
+pub(crate) const TIND_BLOCK: usize = DIND_BLOCK + 1;
+pub(crate) const N_BLOCKS: usize = TIND_BLOCK + 1;
 
 const IFLNK: u16 = 0xA000;
 const IFREG: u16 = 0x8000;
@@ -20,34 +25,115 @@ macro_rules! is_directory {
 macro_rules! is_symbolic_link {
     ($mode: expr) => { (((mode) & IFLNK) != 0) as bool };
 }
+/// Default permission bits stamped on directories created via `new_dir`.
+const DEFAULT_DIR_PERM: u16 = 0o755;
+
 pub struct Inode {
-    i_mode:        u16,
-    i_size:        u64,
-    i_atime:       u64,
-    i_ctime:       u64,
-    i_mtime:       u64,
-    i_links_count: u16,
-    i_blocks:      u16,
-    i_flags:       u32,
-    i_block:       [u16; N_BLOCKS],
-    // 64 bytes
+    pub(crate) i_mode:        u16,
+    pub(crate) i_uid:         u32,
+    pub(crate) i_size:        u64,
+    pub(crate) i_atime:       u64,
+    pub(crate) i_ctime:       u64,
+    pub(crate) i_mtime:       u64,
+    pub(crate) i_gid:         u32,
+    pub(crate) i_links_count: u16,
+    pub(crate) i_blocks:      u16,
+    pub(crate) i_flags:       u32,
+    pub(crate) i_block:       [u16; N_BLOCKS],
 }
 
+/// Access check bits for `access`'s `mask` parameter, mirroring POSIX `R_OK`/`W_OK`/`X_OK`.
+pub(crate) const R_OK: u8 = 0o4;
+pub(crate) const W_OK: u8 = 0o2;
+pub(crate) const X_OK: u8 = 0o1;
 
 impl Inode {
-    pub fn new_dir(time: u64, flags: u32, first_block: u16) -> Inode {
+    pub fn new_dir(time: u64, flags: u32, first_block: u16, uid: u32, gid: u32) -> Inode {
         let mut blocks = [0; N_BLOCKS];
         blocks[0] = first_block;
         Inode {
-            i_mode: IFDIR,
+            i_mode: IFDIR | DEFAULT_DIR_PERM,
+            i_uid: uid,
             i_size: 0,
             i_atime: time,
             i_ctime: time,
             i_mtime: time,
-            i_links_count: 1,
+            i_gid: gid,
+            // one for the parent's entry naming this directory, one for its own "."
+            i_links_count: 2,
             i_blocks: 1,
             i_flags: flags,
             i_block: blocks.clone()
         }
     }
+
+    /// Create an empty regular file owned by `uid`/`gid` with permission bits `perm`.
+    pub fn new_file(time: u64, perm: u16, uid: u32, gid: u32) -> Inode {
+        Inode {
+            i_mode: IFREG | (perm & 0o777),
+            i_uid: uid,
+            i_size: 0,
+            i_atime: time,
+            i_ctime: time,
+            i_mtime: time,
+            i_gid: gid,
+            i_links_count: 1,
+            i_blocks: 0,
+            i_flags: 0,
+            i_block: [0; N_BLOCKS],
+        }
+    }
+
+    /// Create a symlink inode for `target`. If `target` fits within `i_block`
+    /// (`N_BLOCKS * 2` bytes) it is packed inline here (ext2's "fast symlink")
+    /// and `i_blocks` stays 0; otherwise `i_block` is left zeroed for the
+    /// caller to `balloc` a data block, write `target` into it, and set
+    /// `i_block[0]`/`i_blocks` once the block exists.
+    pub fn new_symlink(time: u64, target: &str) -> Inode {
+        let bytes = target.as_bytes();
+        let mut i_block = [0u16; N_BLOCKS];
+        if bytes.len() <= N_BLOCKS * 2 {
+            for (i, chunk) in bytes.chunks(2).enumerate() {
+                let lo = chunk[0];
+                let hi = *chunk.get(1).unwrap_or(&0);
+                i_block[i] = u16::from_le_bytes([lo, hi]);
+            }
+        }
+        Inode {
+            i_mode: IFLNK | 0o777,
+            i_uid: 0,
+            i_size: bytes.len() as u64,
+            i_atime: time,
+            i_ctime: time,
+            i_mtime: time,
+            i_gid: 0,
+            i_links_count: 1,
+            i_blocks: 0,
+            i_flags: 0,
+            i_block,
+        }
+    }
+
+    /// Whether this inode is a symbolic link.
+    pub(crate) fn is_symlink(&self) -> bool {
+        self.i_mode & 0xF000 == IFLNK
+    }
+}
+
+/// Standard owner/group/other POSIX permission resolution: owner bits if `uid`
+/// matches the inode's owner, else group bits if `gid` matches, else other
+/// bits. `uid == 0` (root) always bypasses the check.
+pub(crate) fn access(inode: &Inode, uid: u32, gid: u32, mask: u8) -> bool {
+    if uid == 0 {
+        return true;
+    }
+    let perm = inode.i_mode & 0o777;
+    let bits = if inode.i_uid == uid {
+        (perm >> 6) & 0o7
+    } else if inode.i_gid == gid {
+        (perm >> 3) & 0o7
+    } else {
+        perm & 0o7
+    } as u8;
+    bits & mask == mask
 }
\ No newline at end of file